@@ -1,19 +1,46 @@
-use std::io::{Cursor, Write};
+use std::{
+    ffi::OsString,
+    io::{Cursor, Write},
+};
 
-use clap_complete::{generate, Shell};
+use clap_complete::{
+    engine::{complete as engine_complete, ArgValueCompleter, CompletionCandidate},
+    Shell,
+};
 use probe_rs::Probe;
 use probe_rs_cli_util::{clap::CommandFactory, common_options::OperationError};
 
 use crate::{Cli, CompleteKind};
 
+/// The environment variable the registration stub sets to tell us which word of `COMP_WORDS`
+/// (or its Zsh/Fish equivalent) is the one currently being completed.
+const CLAP_COMPLETE_INDEX_VAR: &str = "_CLAP_COMPLETE_INDEX";
+
 /// Lists all the chips that are available for autocompletion to read.
 ///
-/// Output will be one line per chip and print the full name probe-rs expects.
-pub fn list_chips(mut f: impl Write, starts_with: String) -> Result<(), OperationError> {
+/// Output will be one line per chip and print the full name probe-rs expects. When
+/// `with_description` is set, each line is `name<TAB>description`, the description being the
+/// chip's family and the architecture of its first core (e.g. `STM32F4 Series family, Armv7em`) so
+/// shells that support it can show it next to otherwise hard-to-tell-apart part numbers.
+pub fn list_chips(
+    mut f: impl Write,
+    starts_with: String,
+    with_description: bool,
+) -> Result<(), OperationError> {
     for family in probe_rs::config::families().map_err(OperationError::FailedToReadFamilies)? {
         for variant in family.variants() {
             if variant.name.starts_with(&starts_with) {
-                writeln!(f, "{}", variant.name)?;
+                if with_description {
+                    let core = variant
+                        .cores
+                        .first()
+                        .map_or("unknown core".to_owned(), |core| {
+                            format!("{:?}", core.core_type)
+                        });
+                    writeln!(f, "{}\t{} family, {}", variant.name, family.name, core)?;
+                } else {
+                    writeln!(f, "{}", variant.name)?;
+                }
             }
         }
     }
@@ -23,13 +50,18 @@ pub fn list_chips(mut f: impl Write, starts_with: String) -> Result<(), Operatio
 /// Lists all the probes that are available for autocompletion to read.
 /// This are all the probes that are currently connected.
 ///
-/// Output will be one line per probe and print the PID:VID:SERIAL and the full name.
-pub fn list_probes(mut f: impl Write, starts_with: String) -> Result<(), OperationError> {
+/// Output will be one line per probe and print the VID:PID:SERIAL and the full name. When
+/// `with_description` is set, each line is `name<TAB>description`, the description naming the
+/// probe's driver (e.g. `JLink debug probe`).
+pub fn list_probes(
+    mut f: impl Write,
+    starts_with: String,
+    with_description: bool,
+) -> Result<(), OperationError> {
     let probes = Probe::list_all();
     for probe in probes {
         if probe.identifier.starts_with(&starts_with) {
-            writeln!(
-                f,
+            let candidate = format!(
                 "{vid:04x}\\:{pid:04x}{sn}B[{id} \\[{typ:?}\\] B]",
                 vid = probe.vendor_id,
                 pid = probe.product_id,
@@ -39,98 +71,407 @@ pub fn list_probes(mut f: impl Write, starts_with: String) -> Result<(), Operati
                     .map_or("".to_owned(), |v| format!("\\:{}", v)),
                 id = probe.identifier,
                 typ = probe.probe_type
-            )?;
+            );
+            if with_description {
+                writeln!(f, "{candidate}\t{:?} debug probe", probe.probe_type)?;
+            } else {
+                writeln!(f, "{candidate}")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Completes a static list of values, keeping only those starting with what's typed so far and
+/// optionally tagging each with a description for the shells that can show one.
+fn complete_static(
+    choices: &[(&str, &str)],
+    starts_with: &str,
+    with_description: bool,
+) -> Vec<String> {
+    choices
+        .iter()
+        .filter(|(value, _)| value.starts_with(starts_with))
+        .map(|(value, description)| {
+            if with_description {
+                format!("{value}\t{description}")
+            } else {
+                (*value).to_owned()
+            }
+        })
+        .collect()
+}
+
+fn complete_chip(
+    starts_with: &str,
+    with_description: bool,
+) -> Result<Vec<String>, OperationError> {
+    let mut out = Cursor::new(Vec::new());
+    list_chips(&mut out, starts_with.to_owned(), with_description)?;
+    Ok(String::from_utf8_lossy(out.get_ref())
+        .lines()
+        .map(str::to_owned)
+        .collect())
+}
+
+fn complete_probe(
+    starts_with: &str,
+    with_description: bool,
+) -> Result<Vec<String>, OperationError> {
+    let mut out = Cursor::new(Vec::new());
+    list_probes(&mut out, starts_with.to_owned(), with_description)?;
+    Ok(String::from_utf8_lossy(out.get_ref())
+        .lines()
+        .map(str::to_owned)
+        .collect())
+}
+
+fn complete_protocol(
+    starts_with: &str,
+    with_description: bool,
+) -> Result<Vec<String>, OperationError> {
+    Ok(complete_static(
+        &[
+            ("swd", "Serial Wire Debug"),
+            ("jtag", "Joint Test Action Group"),
+        ],
+        starts_with,
+        with_description,
+    ))
+}
+
+fn complete_speed(
+    starts_with: &str,
+    with_description: bool,
+) -> Result<Vec<String>, OperationError> {
+    Ok(complete_static(
+        &[
+            ("100", "100 kHz"),
+            ("1000", "1 MHz"),
+            ("4000", "4 MHz"),
+            ("8000", "8 MHz"),
+        ],
+        starts_with,
+        with_description,
+    ))
+}
+
+fn complete_format(
+    starts_with: &str,
+    with_description: bool,
+) -> Result<Vec<String>, OperationError> {
+    Ok(complete_static(
+        &[
+            ("bin", "Raw binary"),
+            ("hex", "Intel HEX"),
+            ("elf", "ELF executable"),
+        ],
+        starts_with,
+        with_description,
+    ))
+}
+
+fn complete_log_level(
+    starts_with: &str,
+    with_description: bool,
+) -> Result<Vec<String>, OperationError> {
+    Ok(complete_static(
+        &[
+            ("error", "Only errors"),
+            ("warn", "Errors and warnings"),
+            ("info", "Informational messages"),
+            ("debug", "Verbose debugging output"),
+            ("trace", "Extremely verbose output"),
+        ],
+        starts_with,
+        with_description,
+    ))
+}
+
+/// A completion function: given what's typed so far for an argument (and whether the shell can
+/// show a description column), returns the matching candidates.
+type Completer = fn(&str, bool) -> Result<Vec<String>, OperationError>;
+
+/// Maps clap argument ids to the function that completes them.
+///
+/// This is the single place that wires an argument to dynamic values. Arguments that fall back
+/// to clap's own completion (other flags, fixed `possible_values`, file paths, ...) simply have
+/// no entry here; adding a new dynamically-completed flag is a one-line addition to this table,
+/// not a new regex or a bespoke branch in [`complete`].
+const COMPLETER_REGISTRY: &[(&str, Completer)] = &[
+    ("chip", complete_chip),
+    ("probe-selector", complete_probe),
+    ("probe", complete_probe),
+    ("protocol", complete_protocol),
+    ("speed", complete_speed),
+    ("format", complete_format),
+    ("log-level", complete_log_level),
+];
+
+fn completer_for(arg_id: &str) -> Option<Completer> {
+    COMPLETER_REGISTRY
+        .iter()
+        .find(|(id, _)| *id == arg_id)
+        .map(|(_, completer)| *completer)
+}
+
+/// Attaches an [`ArgValueCompleter`] to every argument in [`COMPLETER_REGISTRY`] that is
+/// actually present on `command` or on any of its subcommands, wiring our live lookups (chips,
+/// probes, ...) into clap's own completion engine instead of replacing it.
+fn with_dynamic_completers(mut command: clap::Command, with_description: bool) -> clap::Command {
+    for &(arg_id, completer) in COMPLETER_REGISTRY {
+        if !command.get_arguments().any(|arg| arg.get_id() == arg_id) {
+            continue;
+        }
+        command = command.mut_arg(arg_id, |arg| {
+            arg.add(ArgValueCompleter::new(move |current: &std::ffi::OsStr| {
+                let current = current.to_string_lossy();
+                completer(&current, with_description)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|line| {
+                        let mut parts = line.splitn(2, '\t');
+                        let value = parts.next().unwrap_or_default().to_owned();
+                        let candidate = CompletionCandidate::new(value);
+                        match parts.next() {
+                            Some(help) => candidate.help(Some(help.to_owned().into())),
+                            None => candidate,
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            }))
+        });
+    }
+    let subcommands: Vec<String> = command
+        .get_subcommands()
+        .map(|sub| sub.get_name().to_owned())
+        .collect();
+    for name in subcommands {
+        command = command.mut_subcommand(name, |sub| {
+            with_dynamic_completers(sub, with_description)
+        });
+    }
+    command
+}
+
+/// Implements the hidden `complete` subcommand that backs dynamic shell completion.
+///
+/// This replaces regex-patching the scripts that `clap_complete::generate` emits: instead, the
+/// registration stub (see [`registration_script`]) just forwards the current command line to
+/// us, and we hand it to `clap_complete`'s own dynamic completion engine. Arguments listed in
+/// [`COMPLETER_REGISTRY`] get a live lookup ([`with_dynamic_completers`]); everything else
+/// (subcommand names, flag names, fixed `possible_values`, file paths, ...) is answered by the
+/// engine exactly as it would be for any other clap application.
+pub fn complete(shell: Shell, words: &[String]) -> Result<(), anyhow::Error> {
+    let index: usize = std::env::var(CLAP_COMPLETE_INDEX_VAR)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(words.len().saturating_sub(1));
+
+    // Zsh, Fish and PowerShell can all render a description column next to a candidate; the
+    // others only ever see the bare value, so there's no point computing one for them.
+    let with_description = matches!(shell, Shell::Zsh | Shell::Fish | Shell::PowerShell);
+
+    let mut command = with_dynamic_completers(<Cli as CommandFactory>::command(), with_description);
+    let args: Vec<OsString> = words.iter().map(OsString::from).collect();
+    let current_dir = std::env::current_dir().ok();
+
+    let candidates = engine_complete(&mut command, args, index, current_dir.as_deref())
+        .map_err(anyhow::Error::from)?;
+
+    // The Bash stub splits our output on `\013` (see its `IFS`); every other stub reads lines.
+    let separator = if matches!(shell, Shell::Bash) {
+        "\u{b}"
+    } else {
+        "\n"
+    };
+    for candidate in candidates {
+        let value = candidate.get_value().to_string_lossy();
+        match candidate.get_help().filter(|_| with_description) {
+            Some(help) => print!("{value}\t{help}{separator}"),
+            None => print!("{value}{separator}"),
         }
     }
+
     Ok(())
 }
 
 pub fn generate_completion(
     shell: Shell,
     kind: CompleteKind,
-    input: String,
+    input: Vec<String>,
 ) -> Result<(), anyhow::Error> {
-    if !matches!(shell, Shell::Zsh | Shell::Bash) {
-        anyhow::bail!("Only ZSH and Bash are supported for autocompletions at the moment");
-    }
-
-    let output = match kind {
+    match kind {
         CompleteKind::GenerateScript => {
-            let mut command = <Cli as CommandFactory>::command();
             let name = std::env::args_os().next().unwrap();
             let name = name.to_str().unwrap().split('/').last().unwrap();
-            command = command.name("probe-rs-cli");
-            let mut script = Cursor::new(Vec::<u8>::new());
-            generate(shell, &mut command, name, &mut script);
-            let mut script = String::from_utf8_lossy(&script.into_inner()).to_string();
-            inject_dynamic_completions(shell, name, &mut script)?;
-            script
-        }
-        CompleteKind::ProbeList => {
-            let mut script = Cursor::new(Vec::<u8>::new());
-            list_probes(&mut script, input)?;
-            String::from_utf8_lossy(&script.into_inner()).to_string()
-        }
-        CompleteKind::ChipList => {
-            let mut script = Cursor::new(Vec::<u8>::new());
-            list_chips(&mut script, input)?;
-            String::from_utf8_lossy(&script.into_inner()).to_string()
+            println!("{}", registration_script(shell, name)?);
         }
-    };
-
-    println!("{output}");
+        CompleteKind::Complete => complete(shell, &input)?,
+    }
 
     Ok(())
 }
 
-fn inject_dynamic_completions(
-    shell: Shell,
-    name: &str,
-    script: &mut String,
-) -> Result<(), anyhow::Error> {
-    match shell {
-        Shell::Zsh => {
-            let re = regex::Regex::new(&format!(r#"(_{name} "\$@")"#))?;
-            let inject = r#"(( $+functions[_probe-rs-cli_chips_list] )) ||
-_probe-rs-cli_chips_list() {
-    array_of_lines=("$${(@f)$$(probe-rs-cli complete zsh chip-list "" )}")
-    _values 'flags' $$array_of_lines
+/// Builds the tiny, shell-specific stub that gets `eval`'d into the user's shell config. It does
+/// not contain any completion logic of its own: it just captures the current command line and
+/// hands it to `probe-rs-cli complete`, then feeds the answer back to the shell.
+fn registration_script(shell: Shell, name: &str) -> Result<String, anyhow::Error> {
+    Ok(match shell {
+        Shell::Bash => format!(
+            r#"_{name}_complete() {{
+    local IFS=$'\013'
+    COMPREPLY=( $({CLAP_COMPLETE_INDEX_VAR}=${{COMP_CWORD}} {name} complete --shell bash -- "${{COMP_WORDS[@]}}") )
+}}
+complete -F _{name}_complete {name}"#
+        ),
+        Shell::Zsh => format!(
+            r#"#compdef {name}
+_{name}_complete() {{
+    local -a lines descriptions
+    lines=("${{(@f)$({CLAP_COMPLETE_INDEX_VAR}=$((CURRENT - 1)) {name} complete --shell zsh -- "${{words[@]}}")}}")
+    for line in $lines; do
+        descriptions+=("${{line/$'\t'/:}}")
+    done
+    _describe 'values' descriptions
+}}
+compdef _{name}_complete {name}"#
+        ),
+        Shell::Fish => format!(
+            r#"function __{name}_complete
+    set -lx {CLAP_COMPLETE_INDEX_VAR} (count (commandline -opc))
+    {name} complete --shell fish -- (commandline -opc) (commandline -ct)
+end
+complete -c {name} -f -a '(__{name}_complete)'"#
+        ),
+        Shell::PowerShell => format!(
+            r#"Register-ArgumentCompleter -Native -CommandName {name} -ScriptBlock {{
+    param($wordToComplete, $commandAst, $cursorPosition)
+    $words = $commandAst.CommandElements | ForEach-Object {{ $_.ToString() }}
+    $env:{CLAP_COMPLETE_INDEX_VAR} = $words.Count
+    {name} complete --shell powershell -- @($words) $wordToComplete | ForEach-Object {{
+        $value, $tooltip = $_ -split "`t", 2
+        [System.Management.Automation.CompletionResult]::new($value, $value, 'ParameterValue', $tooltip ?? $value)
+    }}
+}}"#
+        ),
+        Shell::Elvish => format!(
+            r#"set edit:completion:arg-completer[{name}] = {{|@words|
+    var index = (- (count $words) 1)
+    tmp E:{CLAP_COMPLETE_INDEX_VAR} = $index
+    {name} complete --shell elvish -- $@words
+}}"#
+        ),
+        other => anyhow::bail!("Shell completions are not supported for {other}"),
+    })
 }
-(( $+functions[_probe-rs-cli_probe_list] )) ||
-_probe-rs-cli_probe_list() {
-    array_of_lines=("$${(@f)$$(probe-rs-cli complete zsh probe-list "" )}")
-    if [ $${#array_of_lines[@]} -eq 0 ]; then
-        _values 'flags' $$array_of_lines
-    fi
-}
-            "#;
-            *script = re.replace_all(script, format!("{inject}\n$1")).into();
-
-            let re = regex::Regex::new("(PROBE_SELECTOR: )")?;
-            *script = re
-                .replace_all(script, "PROBE_SELECTOR:_probe-rs-cli_probe_list")
-                .into();
-
-            let re = regex::Regex::new("(CHIP: )")?;
-            *script = re
-                .replace_all(script, "CHIP:_probe-rs-cli_chips_list")
-                .into();
-        }
-        Shell::Bash => {
-            let re = regex::Regex::new(
-                r#"(?s)(\-\-chip\)\n *COMPREPLY=\(\$\()compgen \-f( "\$\{cur\}"\)\))"#,
-            )?;
-            *script = re
-                .replace_all(script, r#"${1}probe-rs-cli complete chip-list $2"#)
-                .into();
-            let re = regex::Regex::new(
-                r#"(?s)(\-\-probe\)\n *COMPREPLY=\(\$\()compgen \-f( "\$\{cur\}"\)\))"#,
-            )?;
-            *script = re
-                .replace_all(script, r#"${1}probe-rs-cli complete probe-list $2"#)
-                .into();
-        }
-        _ => {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn complete_static_filters_by_prefix() {
+        let choices = [
+            ("swd", "Serial Wire Debug"),
+            ("jtag", "Joint Test Action Group"),
+        ];
+        assert_eq!(complete_static(&choices, "s", false), vec!["swd"]);
+        assert_eq!(
+            complete_static(&choices, "", false),
+            vec!["swd".to_owned(), "jtag".to_owned()]
+        );
+        assert!(complete_static(&choices, "nope", false).is_empty());
+    }
+
+    #[test]
+    fn complete_static_appends_tab_separated_description_when_requested() {
+        let choices = [("swd", "Serial Wire Debug")];
+        assert_eq!(complete_static(&choices, "", false), vec!["swd"]);
+        assert_eq!(
+            complete_static(&choices, "", true),
+            vec!["swd\tSerial Wire Debug"]
+        );
+    }
+
+    #[test]
+    fn complete_protocol_speed_format_and_log_level_are_prefix_filtered() {
+        assert_eq!(complete_protocol("jt", false).unwrap(), vec!["jtag"]);
+        assert_eq!(complete_speed("4", false).unwrap(), vec!["4000"]);
+        assert_eq!(complete_format("e", false).unwrap(), vec!["elf"]);
+        assert_eq!(
+            complete_log_level("de", false).unwrap(),
+            vec!["debug".to_owned()]
+        );
+    }
+
+    #[test]
+    fn completer_for_resolves_known_argument_ids() {
+        assert!(completer_for("chip").is_some());
+        assert!(completer_for("probe-selector").is_some());
+        assert!(completer_for("probe").is_some());
+        assert!(completer_for("protocol").is_some());
+        assert!(completer_for("speed").is_some());
+        assert!(completer_for("format").is_some());
+        assert!(completer_for("log-level").is_some());
+    }
+
+    #[test]
+    fn completer_for_returns_none_for_unknown_argument_ids() {
+        assert!(completer_for("not-a-real-argument").is_none());
+    }
+
+    #[test]
+    fn complete_chip_never_panics_and_respects_the_prefix() {
+        let candidates = complete_chip("definitely-not-a-real-chip-prefix", false).unwrap();
+        assert!(candidates.is_empty());
+    }
+
+    #[test]
+    fn complete_probe_never_panics_and_respects_the_prefix() {
+        let candidates = complete_probe("definitely-not-a-real-probe-prefix", false).unwrap();
+        assert!(candidates.is_empty());
+    }
+
+    #[test]
+    fn bash_registration_script_reads_comp_cword_before_running() {
+        let script = registration_script(Shell::Bash, "probe-rs-cli").unwrap();
+        assert!(script.contains("local IFS=$'\\013'"));
+        assert!(script.contains(
+            "COMPREPLY=( $(_CLAP_COMPLETE_INDEX=${COMP_CWORD} probe-rs-cli complete --shell bash -- \"${COMP_WORDS[@]}\") )"
+        ));
+    }
+
+    #[test]
+    fn zsh_registration_script_uses_a_valid_describe_invocation() {
+        let script = registration_script(Shell::Zsh, "probe-rs-cli").unwrap();
+        // `_describe -V` takes the group name as its own argument; passing just one array
+        // afterwards leaves `_describe` without its required `array` argument (see chunk0-3).
+        assert!(!script.contains("_describe -V 'values' descriptions"));
+        assert!(script.contains("_describe 'values' descriptions"));
+    }
+
+    #[test]
+    fn fish_registration_script_computes_the_index_of_the_word_under_the_cursor() {
+        let script = registration_script(Shell::Fish, "probe-rs-cli").unwrap();
+        // `commandline -opc` only returns the words *before* the cursor, so the word being
+        // completed sits at that count, not one past it (see chunk0-2).
+        assert!(script.contains("set -lx _CLAP_COMPLETE_INDEX (count (commandline -opc))"));
+        assert!(!script.contains("+ 1"));
+    }
+
+    #[test]
+    fn powershell_registration_script_registers_an_argument_completer() {
+        let script = registration_script(Shell::PowerShell, "probe-rs-cli").unwrap();
+        assert!(script.contains("Register-ArgumentCompleter -Native -CommandName probe-rs-cli"));
+        assert!(script.contains("[System.Management.Automation.CompletionResult]"));
+    }
+
+    #[test]
+    fn elvish_registration_script_uses_tmp_env_instead_of_a_posix_prefix_assignment() {
+        let script = registration_script(Shell::Elvish, "probe-rs-cli").unwrap();
+        // Elvish has no `VAR=value cmd` prefix-assignment form; only `set`/`tmp` on `E:` work.
+        assert!(!script.contains("_CLAP_COMPLETE_INDEX=$index"));
+        assert!(script.contains("tmp E:_CLAP_COMPLETE_INDEX = $index"));
     }
-    Ok(())
 }